@@ -1,5 +1,7 @@
 use std::alloc::{GlobalAlloc, Layout, System};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 
 pub const MAX_DEPTH: usize = 64;
 
@@ -18,10 +20,233 @@ thread_local! {
     pub static DO_COUNT: RefCell<u32> = RefCell::new(0);
 }
 
-struct CountingAllocator;
+// A stack of "fail after n more allocations" countdowns, one per nested
+// `simulate_oom_after` scope; the innermost scope is the last element.
+thread_local! {
+    static FAIL_AFTER: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+// Guards against re-entering `should_simulate_oom` due to an allocation made while
+// consulting `FAIL_AFTER` itself (e.g. growing its backing `Vec`).
+thread_local! {
+    static HANDLING_OOM_CHECK: Cell<bool> = const { Cell::new(false) };
+}
+
+// Growing/shrinking `FAIL_AFTER`'s backing `Vec` can itself allocate, which would
+// otherwise re-enter `should_simulate_oom` and try to borrow `FAIL_AFTER` a second time
+// while this borrow is still live. Guard with `HANDLING_OOM_CHECK` so a re-entrant call
+// sees it set and bails out before touching `FAIL_AFTER` at all.
+
+pub(crate) fn push_oom_after(n: u64) {
+    HANDLING_OOM_CHECK.with(|handling| handling.set(true));
+    FAIL_AFTER.with(|stack| stack.borrow_mut().push(n));
+    HANDLING_OOM_CHECK.with(|handling| handling.set(false));
+}
+
+pub(crate) fn pop_oom_after() {
+    HANDLING_OOM_CHECK.with(|handling| handling.set(true));
+    FAIL_AFTER.with(|stack| {
+        stack.borrow_mut().pop();
+    });
+    HANDLING_OOM_CHECK.with(|handling| handling.set(false));
+}
+
+/// Returns whether the allocation about to be performed should instead simulate an
+/// out-of-memory condition, decrementing the innermost active `simulate_oom_after`
+/// countdown. Allocations made by the crate's own bookkeeping (the countdown stack and the
+/// backtrace capture) never fail and do not count down.
+fn should_simulate_oom() -> bool {
+    if HANDLING_OOM_CHECK.with(Cell::get) || CAPTURING_BACKTRACE.with(Cell::get) {
+        return false;
+    }
+    HANDLING_OOM_CHECK.with(|handling| handling.set(true));
+    let should_fail = FAIL_AFTER.with(|stack| match stack.borrow_mut().last_mut() {
+        Some(remaining) if *remaining == 0 => true,
+        Some(remaining) => {
+            *remaining -= 1;
+            false
+        }
+        None => false,
+    });
+    HANDLING_OOM_CHECK.with(|handling| handling.set(false));
+    should_fail
+}
+
+// One captured allocation call site: the raw instruction pointers of the capturing
+// backtrace (symbolized lazily, only once the region is torn down) plus its tally.
+struct CallSite {
+    ips: Vec<usize>,
+    stats: crate::BacktraceAllocations,
+}
+
+// A stack of call-site tables, one per nested `measure_with_backtraces` region; the
+// innermost region is the last element.
+thread_local! {
+    static CALL_SITES: RefCell<Vec<HashMap<u64, CallSite>>> = const { RefCell::new(Vec::new()) };
+}
+// Guards the backtrace capture itself (which allocates) against being attributed to a
+// call site and recursing back into `record_call_site`.
+thread_local! {
+    static CAPTURING_BACKTRACE: Cell<bool> = const { Cell::new(false) };
+}
+
+// Growing/shrinking `CALL_SITES`'s backing `Vec` can itself allocate, which would
+// otherwise re-enter `alloc`'s `CALL_SITES` check (and, if a region is already active, try
+// to borrow `CALL_SITES` a second time while this borrow is still live). Guard with
+// `CAPTURING_BACKTRACE` so a re-entrant call sees it set and bails out before touching
+// `CALL_SITES` at all, and with `DO_COUNT` so that allocation isn't attributed to whatever
+// `measure`/`measure_global` region is active around this call either.
+
+pub(crate) fn push_backtrace_region() {
+    CAPTURING_BACKTRACE.with(|capturing| capturing.set(true));
+    DO_COUNT.with(|b| *b.borrow_mut() += 1);
+    CALL_SITES.with(|regions| regions.borrow_mut().push(HashMap::new()));
+    DO_COUNT.with(|b| *b.borrow_mut() -= 1);
+    CAPTURING_BACKTRACE.with(|capturing| capturing.set(false));
+}
+
+pub(crate) fn pop_backtrace_region() -> HashMap<String, crate::BacktraceAllocations> {
+    // Keep both guards up for the *entire* body, not just the `CALL_SITES` pop: `symbolize`
+    // (loading/parsing debug info to resolve each call site's instruction pointers) allocates
+    // too, and without `DO_COUNT` suppression those allocations would get attributed to
+    // whatever `measure`/`measure_global` region is still active around this call (e.g. an
+    // enclosing `measure()`, or an outer `measure_with_backtraces` region).
+    CAPTURING_BACKTRACE.with(|capturing| capturing.set(true));
+    DO_COUNT.with(|b| *b.borrow_mut() += 1);
+
+    let region = CALL_SITES
+        .with(|regions| regions.borrow_mut().pop())
+        .unwrap_or_default();
+    // Distinct call sites (distinguished by raw instruction pointers) can still symbolize to
+    // the same function name, e.g. two allocations made from the same function: merge their
+    // stats rather than letting one silently overwrite the other.
+    let mut by_name: HashMap<String, crate::BacktraceAllocations> = HashMap::new();
+    for call_site in region.into_values() {
+        let stats = by_name.entry(symbolize(&call_site.ips)).or_default();
+        stats.num_allocations += call_site.stats.num_allocations;
+        stats.total_bytes_allocated += call_site.stats.total_bytes_allocated;
+    }
+
+    DO_COUNT.with(|b| *b.borrow_mut() -= 1);
+    CAPTURING_BACKTRACE.with(|capturing| capturing.set(false));
+    by_name
+}
 
-unsafe impl GlobalAlloc for CountingAllocator {
+fn symbolize(ips: &[usize]) -> String {
+    for &ip in ips {
+        let mut name = None;
+        backtrace::resolve(ip as *mut std::ffi::c_void, |symbol| {
+            if name.is_none() {
+                name = symbol.name().map(|n| n.to_string());
+            }
+        });
+        match name {
+            Some(name) if !name.starts_with("allocation_counter::") => return name,
+            _ => continue,
+        }
+    }
+    "<unknown>".to_string()
+}
+
+// Attribute an allocation of `bytes` to its call site, capturing a backtrace. Keeps
+// `CAPTURING_BACKTRACE` set for the *entire* body, not just the capture: both walking the
+// stack and recording the result (which clones `ips` into a fresh `CallSite` and may grow
+// `CALL_SITES`'s `HashMap`) can allocate, and none of that must be attributed to a call
+// site itself or recurse back in here. Also suppresses ordinary counting via `DO_COUNT`
+// (the same mechanism `avoid_counting` uses), so this bookkeeping doesn't inflate the
+// region's own `AllocationInfo`.
+fn record_call_site(bytes: u64) {
+    if CAPTURING_BACKTRACE.with(Cell::get) {
+        return;
+    }
+    CAPTURING_BACKTRACE.with(|capturing| capturing.set(true));
+    DO_COUNT.with(|b| *b.borrow_mut() += 1);
+
+    let mut ips = Vec::new();
+    backtrace::trace(|frame| {
+        ips.push(frame.ip() as usize);
+        true
+    });
+
+    let key = {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        ips.hash(&mut hasher);
+        hasher.finish()
+    };
+
+    CALL_SITES.with(|regions| {
+        let mut regions = regions.borrow_mut();
+        if let Some(region) = regions.last_mut() {
+            let call_site = region.entry(key).or_insert_with(|| CallSite {
+                ips: ips.clone(),
+                stats: crate::BacktraceAllocations::default(),
+            });
+            call_site.stats.num_allocations += 1;
+            call_site.stats.total_bytes_allocated += bytes;
+        }
+    });
+
+    DO_COUNT.with(|b| *b.borrow_mut() -= 1);
+    CAPTURING_BACKTRACE.with(|capturing| capturing.set(false));
+}
+
+/// Whether the global, cross-thread counting mode (see `measure_global`) is currently active.
+pub static GLOBAL_COUNTING: AtomicBool = AtomicBool::new(false);
+pub static GLOBAL_NUM_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+pub static GLOBAL_TOTAL_BYTES_ALLOCATED: AtomicU64 = AtomicU64::new(0);
+pub static GLOBAL_CURRENT_BYTES_ALLOCATED: AtomicI64 = AtomicI64::new(0);
+pub static GLOBAL_MAX_BYTES_ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+/// Update `max` to `current` if `current` is larger, via a CAS loop so concurrent
+/// updates from other threads can't clobber a higher value that was just set.
+fn update_global_max(current_bytes: i64) {
+    if current_bytes <= 0 {
+        return;
+    }
+    let current_bytes = current_bytes as u64;
+    let mut prev = GLOBAL_MAX_BYTES_ALLOCATED.load(Ordering::Relaxed);
+    while current_bytes > prev {
+        match GLOBAL_MAX_BYTES_ALLOCATED.compare_exchange_weak(
+            prev,
+            current_bytes,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => break,
+            Err(observed) => prev = observed,
+        }
+    }
+}
+
+/// A [`GlobalAlloc`] decorator that counts allocations performed through it while
+/// delegating the actual (de)allocation to an inner allocator `A`.
+///
+/// `A` defaults to [`System`], matching the common case of counting the system
+/// allocator, but any other `GlobalAlloc` (jemalloc, mimalloc, a custom arena, ...) can
+/// be wrapped instead:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static GLOBAL: allocation_counter::CountingAllocator<MyArena> =
+///     allocation_counter::CountingAllocator::new(MyArena::new());
+/// ```
+pub struct CountingAllocator<A: GlobalAlloc = System> {
+    inner: A,
+}
+
+impl<A: GlobalAlloc> CountingAllocator<A> {
+    /// Wrap `inner`, counting all allocations performed through it.
+    pub const fn new(inner: A) -> Self {
+        CountingAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
     unsafe fn alloc(&self, l: Layout) -> *mut u8 {
+        if should_simulate_oom() {
+            return std::ptr::null_mut();
+        }
+
         DO_COUNT.with(|b| {
             if *b.borrow() == 0 {
                 ALLOCATIONS.with(|info_stack| {
@@ -29,21 +254,35 @@ unsafe impl GlobalAlloc for CountingAllocator {
                     let depth = info_stack.depth;
                     let info = &mut info_stack.elements[depth as usize];
 
-                    info.count_total += 1;
-                    info.count_current += 1;
-                    if info.count_current > 0 {
-                        info.count_max = info.count_max.max(info.count_current as u64);
-                    }
-                    info.bytes_total += l.size() as u64;
-                    info.bytes_current += l.size() as i64;
-                    if info.bytes_current > 0 {
-                        info.bytes_max = info.bytes_max.max(info.bytes_current as u64);
+                    info.num_allocations += 1;
+                    info.total_bytes_allocated += l.size() as u64;
+                    info.current_bytes_allocated += l.size() as i64;
+                    if info.current_bytes_allocated > 0 {
+                        info.max_bytes_allocated = info
+                            .max_bytes_allocated
+                            .max(info.current_bytes_allocated as u64);
                     }
+                    info.size_class_histogram[crate::size_class(l.size() as u64)] += 1;
                 });
+
+                if GLOBAL_COUNTING.load(Ordering::Relaxed) {
+                    GLOBAL_NUM_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+                    GLOBAL_TOTAL_BYTES_ALLOCATED.fetch_add(l.size() as u64, Ordering::Relaxed);
+                    let current_bytes =
+                        GLOBAL_CURRENT_BYTES_ALLOCATED.fetch_add(l.size() as i64, Ordering::Relaxed)
+                            + l.size() as i64;
+                    update_global_max(current_bytes);
+                }
+
+                if !CAPTURING_BACKTRACE.with(Cell::get)
+                    && CALL_SITES.with(|regions| !regions.borrow().is_empty())
+                {
+                    record_call_site(l.size() as u64);
+                }
             }
         });
 
-        System.alloc(l)
+        self.inner.alloc(l)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, l: Layout) {
@@ -53,15 +292,122 @@ unsafe impl GlobalAlloc for CountingAllocator {
                     let mut info_stack = info_stack.borrow_mut();
                     let depth = info_stack.depth;
                     let info = &mut info_stack.elements[depth as usize];
-                    info.count_current -= 1;
-                    info.bytes_current -= l.size() as i64;
+                    info.current_bytes_allocated -= l.size() as i64;
                 });
+
+                if GLOBAL_COUNTING.load(Ordering::Relaxed) {
+                    // Note: this can momentarily go negative if the deallocation belongs to an
+                    // allocation made before the measured region started (e.g. a `Vec` that was
+                    // built before `measure_global` was called and is dropped inside it).
+                    GLOBAL_CURRENT_BYTES_ALLOCATED.fetch_sub(l.size() as i64, Ordering::Relaxed);
+                }
             }
         });
 
-        System.dealloc(ptr, l);
+        self.inner.dealloc(ptr, l);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, l: Layout, new_size: usize) -> *mut u8 {
+        if should_simulate_oom() {
+            return std::ptr::null_mut();
+        }
+
+        DO_COUNT.with(|b| {
+            if *b.borrow() == 0 {
+                let bytes_delta = new_size as i64 - l.size() as i64;
+
+                ALLOCATIONS.with(|info_stack| {
+                    let mut info_stack = info_stack.borrow_mut();
+                    let depth = info_stack.depth;
+                    let info = &mut info_stack.elements[depth as usize];
+
+                    info.num_reallocations += 1;
+                    if bytes_delta > 0 {
+                        info.bytes_grown += bytes_delta as u64;
+                    } else {
+                        info.bytes_shrunk += (-bytes_delta) as u64;
+                    }
+                    info.current_bytes_allocated += bytes_delta;
+                    if info.current_bytes_allocated > 0 {
+                        info.max_bytes_allocated = info
+                            .max_bytes_allocated
+                            .max(info.current_bytes_allocated as u64);
+                    }
+                    info.size_class_histogram[crate::size_class(new_size as u64)] += 1;
+                });
+
+                if GLOBAL_COUNTING.load(Ordering::Relaxed) {
+                    let current_bytes = GLOBAL_CURRENT_BYTES_ALLOCATED
+                        .fetch_add(bytes_delta, Ordering::Relaxed)
+                        + bytes_delta;
+                    update_global_max(current_bytes);
+                }
+            }
+        });
+
+        self.inner.realloc(ptr, l, new_size)
     }
 }
 
 #[global_allocator]
-static GLOBAL: CountingAllocator = CountingAllocator {};
+static GLOBAL: CountingAllocator<System> = CountingAllocator::new(System);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    // A `GlobalAlloc` that delegates to `System` but counts how many times each method was
+    // invoked, to verify `CountingAllocator<A>` actually forwards to its inner allocator `A`
+    // instead of e.g. always using `System` regardless of what it was constructed with.
+    struct SpyAllocator {
+        alloc_calls: AtomicUsize,
+        dealloc_calls: AtomicUsize,
+        realloc_calls: AtomicUsize,
+    }
+
+    unsafe impl GlobalAlloc for SpyAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.alloc_calls.fetch_add(1, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.dealloc_calls.fetch_add(1, Ordering::Relaxed);
+            System.dealloc(ptr, layout);
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            self.realloc_calls.fetch_add(1, Ordering::Relaxed);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[test]
+    fn test_counting_allocator_delegates_to_inner() {
+        let counting = CountingAllocator::new(SpyAllocator {
+            alloc_calls: AtomicUsize::new(0),
+            dealloc_calls: AtomicUsize::new(0),
+            realloc_calls: AtomicUsize::new(0),
+        });
+
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let grown_layout = Layout::from_size_align(16, 8).unwrap();
+        let info = crate::measure(|| unsafe {
+            let ptr = counting.alloc(layout);
+            assert!(!ptr.is_null());
+            let ptr = counting.realloc(ptr, layout, grown_layout.size());
+            assert!(!ptr.is_null());
+            counting.dealloc(ptr, grown_layout);
+        });
+
+        assert_eq!(counting.inner.alloc_calls.load(Ordering::Relaxed), 1);
+        assert_eq!(counting.inner.realloc_calls.load(Ordering::Relaxed), 1);
+        assert_eq!(counting.inner.dealloc_calls.load(Ordering::Relaxed), 1);
+
+        // The delegation itself doesn't bypass counting: the same allocations are still
+        // attributed to the enclosing `measure` region.
+        assert_eq!(info.num_allocations(), 1);
+        assert_eq!(info.num_reallocations(), 1);
+    }
+}